@@ -46,7 +46,7 @@ extern crate libc;
 
 use std::string::String;
 use std::ffi::CString;
-use std::{mem, slice};
+use std::{marker, mem, ptr, slice};
 
 /// A generic trait for converting a *const c_str to another Rust type
 pub trait FromCStr {
@@ -54,6 +54,14 @@ pub trait FromCStr {
     unsafe fn from_c_str(c_str: *const libc::c_char) -> Self;
     /// the same as from_c_str but for old code compatibility
     unsafe fn from_raw_buf(c_str: *const u8) -> Self;
+    /// Copy the c_str into the returned type, scanning at most `max_len` bytes
+    /// for the terminating NUL.
+    ///
+    /// Returns `None` if no NUL is found within `max_len` bytes, giving callers
+    /// a safe way to consume untrusted buffers of known maximum size without
+    /// reading past the limit.
+    unsafe fn from_c_str_bounded(c_str: *const libc::c_char, max_len: usize) -> Option<Self>
+        where Self: Sized;
 }
 
 /// A generic trait for converting a value to a CString.
@@ -184,6 +192,22 @@ impl FromCStr for String {
     unsafe fn from_raw_buf(c_str: *const u8) -> String {
         FromCStr::from_c_str(c_str as *const libc::c_char)
     }
+
+    #[inline]
+    unsafe fn from_c_str_bounded(c_str: *const libc::c_char, max_len: usize) -> Option<String> {
+        let mut count = 0isize;
+
+        while (count as usize) < max_len {
+            let tmp = ::std::intrinsics::offset(c_str, count);
+
+            if *tmp == 0i8 {
+                let v: Vec<u8> = Vec::from_raw_buf(c_str as *const u8, count as usize);
+                return Some(String::from_utf8_unchecked(v));
+            }
+            count += 1;
+        }
+        None
+    }
 }
 
 impl FromCStr for CString {
@@ -212,6 +236,59 @@ impl FromCStr for CString {
     unsafe fn from_raw_buf(c_str: *const u8) -> CString {
         FromCStr::from_c_str(c_str as *const libc::c_char)
     }
+
+    #[inline]
+    unsafe fn from_c_str_bounded(c_str: *const libc::c_char, max_len: usize) -> Option<CString> {
+        let mut count = 0isize;
+
+        while (count as usize) < max_len {
+            let tmp = ::std::intrinsics::offset(c_str, count);
+
+            if *tmp == 0i8 {
+                let v: Vec<u8> = Vec::from_raw_buf(c_str as *const u8, count as usize);
+                return Some(CString::new(v).unwrap());
+            }
+            count += 1;
+        }
+        None
+    }
+}
+
+/// Returns a borrowed view of the bytes of a C string, not including the
+/// terminating NUL.
+///
+/// The length is computed with a single forward scan for the NUL byte, and the
+/// returned slice borrows the original buffer for the lifetime of `ptr` rather
+/// than copying it. This lets callers read a C-returned string (eg the result
+/// of `getenv`) without a heap round-trip.
+pub unsafe fn c_str_to_bytes<'a>(ptr: &'a *const libc::c_char) -> &'a [u8] {
+    let mut count = 0isize;
+
+    loop {
+        let tmp = ::std::intrinsics::offset(*ptr, count);
+
+        if *tmp == 0i8 {
+            break;
+        }
+        count += 1;
+    }
+    slice::from_raw_parts(*ptr as *const u8, count as usize)
+}
+
+/// Same as `c_str_to_bytes`, but the returned slice includes the terminating
+/// NUL byte.
+pub unsafe fn c_str_to_bytes_with_nul<'a>(ptr: &'a *const libc::c_char) -> &'a [u8] {
+    let bytes = c_str_to_bytes(ptr);
+
+    slice::from_raw_parts(*ptr as *const u8, bytes.len() + 1)
+}
+
+/// Borrowed variant of `FromCStr` that validates the bytes as UTF-8.
+///
+/// Returns `None` when the C string is not valid UTF-8. On success the `&str`
+/// borrows straight from `c_str_to_bytes`, so the check is the only work done.
+pub unsafe fn c_str_to_str<'a>(ptr: &'a *const libc::c_char) -> Option<&'a str> {
+    ::std::str::from_utf8(c_str_to_bytes(ptr)).ok()
 }
 
 // The length of the stack allocated buffer for `vec.with_c_str()`
@@ -274,8 +351,7 @@ unsafe fn with_c_str<T, F>(v: &[u8], checked: bool, f: F) -> T where
 {
     let c_str = if v.len() < BUF_LEN {
         let mut buf: [u8; BUF_LEN] = mem::uninitialized();
-        let mut copy_: Vec<u8> = Vec::from(v);
-        slice::bytes::copy_memory(&mut buf, copy_.as_mut_slice());
+        ptr::copy_nonoverlapping(v.as_ptr(), buf.as_mut_ptr(), v.len());
         buf[v.len()] = 0;
 
         let buf = buf.as_mut_ptr();
@@ -303,16 +379,29 @@ fn check_for_null(v: &[u8], buf: *mut libc::c_char) {
     }
 }
 
-/// External iterator for a CString's bytes.
+/// External iterator for the bytes of a raw C string.
 ///
-/// Use with the `std::iter` module.
-/*#[allow(raw_pointer_deriving)]
+/// Created with `c_chars`, it advances a borrowed `*const libc::c_char` and
+/// yields each `libc::c_char` until the terminating NUL, without copying the
+/// string into a `String` or `CString` first. Use with the `std::iter` module.
+#[allow(raw_pointer_deriving)]
 #[derive(Clone)]
 pub struct CChars<'a> {
     ptr: *const libc::c_char,
     marker: marker::ContravariantLifetime<'a>,
 }
 
+/// Returns an iterator over the bytes of the C string pointed to by `ptr`.
+///
+/// The iterator walks `ptr` in place and is bounded by its lifetime, so no
+/// intermediate `String` or `CString` is built.
+pub unsafe fn c_chars<'a>(ptr: &'a *const libc::c_char) -> CChars<'a> {
+    CChars {
+        ptr: *ptr,
+        marker: marker::ContravariantLifetime,
+    }
+}
+
 impl<'a> Iterator for CChars<'a> {
     type Item = libc::c_char;
 
@@ -325,7 +414,7 @@ impl<'a> Iterator for CChars<'a> {
             Some(ch)
         }
     }
-}*/
+}
 
 /// Parses a C "multistring", eg windows env values or
 /// the req->ptr result in a uv_fs_readdir() call.
@@ -333,10 +422,15 @@ impl<'a> Iterator for CChars<'a> {
 /// Optionally, a `count` can be passed in, limiting the
 /// parsing to only being done `count`-times.
 ///
+/// `max_total_len` caps the total number of bytes scanned across the whole
+/// block; when the cap is reached the walk halts instead of running off the
+/// end of a corrupt block. Pass `None` for the historic unbounded behaviour.
+///
 /// The specified closure is invoked with each string that
 /// is found, and the number of strings found is returned.
 pub unsafe fn from_c_multistring<F>(buf: *const libc::c_char,
                                     count: Option<usize>,
+                                    max_total_len: Option<usize>,
                                     mut f: F)
                                     -> usize where
     F: FnMut(&CString),
@@ -344,17 +438,27 @@ pub unsafe fn from_c_multistring<F>(buf: *const libc::c_char,
 
     let mut curr_ptr: usize = buf as usize;
     let mut ctr = 0;
+    let mut scanned = 0usize;
     let (limited_count, limit) = match count {
         Some(limit) => (true, limit),
         None => (false, 0)
     };
+    let (limited_len, len_limit) = match max_total_len {
+        Some(limit) => (true, limit),
+        None => (false, 0)
+    };
     while ((limited_count && ctr < limit) || !limited_count)
+          && (!limited_len || scanned < len_limit)
           && *(curr_ptr as *const libc::c_char) != 0 as libc::c_char {
         let mut v : Vec<u8> = Vec::new();
         let mut decal = 0isize;
 
         loop {
+            if limited_len && scanned >= len_limit {
+                return ctr;
+            }
             let tmp : u8 = *::std::intrinsics::offset(curr_ptr as *const libc::c_uchar, decal);
+            scanned += 1;
             if tmp == 0u8 {
                 break;
             }
@@ -369,6 +473,188 @@ pub unsafe fn from_c_multistring<F>(buf: *const libc::c_char,
     return ctr;
 }
 
+/// A NUL-terminated, owned buffer of `u16` code units for use with the Win32
+/// `*W` APIs.
+///
+/// Like `CString`, the buffer owns its memory and carries a trailing zero; it
+/// may not contain an interior zero, since that would truncate the string as
+/// seen by C.
+pub struct WideCString {
+    inner: Vec<u16>,
+}
+
+impl WideCString {
+    /// Creates a `WideCString` from a slice of `u16` code units.
+    ///
+    /// # Panics
+    ///
+    /// Panics the task if the slice has an interior zero.
+    pub fn new(units: &[u16]) -> WideCString {
+        assert!(!units.contains(&0u16));
+        unsafe { WideCString::new_unchecked(units) }
+    }
+
+    /// Unsafe variant of `new()` that doesn't check for interior zeros.
+    pub unsafe fn new_unchecked(units: &[u16]) -> WideCString {
+        let mut inner = Vec::with_capacity(units.len() + 1);
+        inner.extend(units.iter().cloned());
+        inner.push(0u16);
+        WideCString { inner: inner }
+    }
+
+    /// Returns a pointer to the NUL-terminated `u16` buffer.
+    pub fn as_ptr(&self) -> *const u16 {
+        self.inner.as_ptr()
+    }
+
+    /// Returns the code units of the string, not including the trailing zero.
+    pub fn as_units(&self) -> &[u16] {
+        &self.inner[..self.inner.len() - 1]
+    }
+}
+
+/// A generic trait for converting a value to a `WideCString`.
+///
+/// This is the UTF-16 counterpart of `ToCStr`, intended for the Win32 `*W`
+/// APIs which expect NUL-terminated `u16` buffers.
+pub trait ToWideCStr {
+    /// Copy the receiver into a `WideCString`.
+    ///
+    /// # Panics
+    ///
+    /// Panics the task if the receiver has an interior null.
+    fn to_wide_c_str(&self) -> WideCString;
+
+    /// Work with a temporary `WideCString` constructed from the receiver.
+    #[inline]
+    fn with_wide_c_str<T, F>(&self, f: F) -> T where
+        F: FnOnce(*const u16) -> T,
+    {
+        let c_str = self.to_wide_c_str();
+        f(c_str.as_ptr())
+    }
+}
+
+impl ToWideCStr for str {
+    #[inline]
+    fn to_wide_c_str(&self) -> WideCString {
+        let units: Vec<u16> = self.utf16_units().collect();
+        WideCString::new(&units)
+    }
+}
+
+impl ToWideCStr for String {
+    #[inline]
+    fn to_wide_c_str(&self) -> WideCString {
+        (**self).to_wide_c_str()
+    }
+}
+
+impl ToWideCStr for [u16] {
+    #[inline]
+    fn to_wide_c_str(&self) -> WideCString {
+        WideCString::new(self)
+    }
+}
+
+/// Parses a wide C "multistring", eg a Windows environment block.
+///
+/// Each entry is terminated by a `0u16`, and the block itself is terminated by
+/// an empty entry (a double NUL). This is the `u16` counterpart of
+/// `from_c_multistring`: optionally a `count` limits the number of entries
+/// parsed, the closure is invoked with each entry, and the number of entries
+/// found is returned.
+pub unsafe fn from_wide_c_multistring<F>(buf: *const u16,
+                                         count: Option<usize>,
+                                         mut f: F)
+                                         -> usize where
+    F: FnMut(&WideCString),
+{
+    let mut curr_ptr = buf;
+    let mut ctr = 0;
+    let (limited_count, limit) = match count {
+        Some(limit) => (true, limit),
+        None => (false, 0)
+    };
+    while ((limited_count && ctr < limit) || !limited_count) && *curr_ptr != 0u16 {
+        let mut v: Vec<u16> = Vec::new();
+        let mut decal = 0isize;
+
+        loop {
+            let tmp = *curr_ptr.offset(decal);
+            if tmp == 0u16 {
+                break;
+            }
+            v.push(tmp);
+            decal += 1;
+        }
+        let cstr = WideCString::new(&v);
+        f(&cstr);
+        curr_ptr = curr_ptr.offset(decal + 1);
+        ctr += 1;
+    }
+    return ctr;
+}
+
+/// Pull-based iterator over the entries of a C "multistring".
+///
+/// Created with `c_multistring_iter`, it yields a borrowed `&[u8]` slice (not
+/// including the terminating NUL) for each entry, advancing the underlying
+/// pointer by each entry's length plus one and stopping at the empty entry
+/// that terminates the block or after `count` entries. This composes with the
+/// `std::iter` module, so an environment block can be collected in a single
+/// expression instead of mutating captured state.
+#[allow(raw_pointer_deriving)]
+#[derive(Clone)]
+pub struct MultiString<'a> {
+    ptr: *const libc::c_char,
+    count: Option<usize>,
+    seen: usize,
+    marker: marker::ContravariantLifetime<'a>,
+}
+
+/// Returns a pull-based iterator over the entries of the multistring pointed to
+/// by `buf`.
+///
+/// As with `from_c_multistring`, an optional `count` limits the number of
+/// entries parsed. Each yielded slice points back into `buf` itself, so
+/// collecting the block copies nothing beyond what the caller chooses to keep.
+pub unsafe fn c_multistring_iter<'a>(buf: *const libc::c_char,
+                                     count: Option<usize>)
+                                     -> MultiString<'a> {
+    MultiString {
+        ptr: buf,
+        count: count,
+        seen: 0,
+        marker: marker::ContravariantLifetime,
+    }
+}
+
+impl<'a> Iterator for MultiString<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if let Some(limit) = self.count {
+            if self.seen >= limit {
+                return None;
+            }
+        }
+        unsafe {
+            if *self.ptr == 0 as libc::c_char {
+                return None;
+            }
+            let mut decal = 0isize;
+            while *self.ptr.offset(decal) != 0 as libc::c_char {
+                decal += 1;
+            }
+            let entry = slice::from_raw_parts(self.ptr as *const u8, decal as usize);
+            self.ptr = self.ptr.offset(decal + 1);
+            self.seen += 1;
+            Some(entry)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -384,7 +670,7 @@ mod tests {
             let ptr = input.as_ptr();
             let expected = ["zero", "one"];
             let mut it = expected.iter();
-            let result = from_c_multistring(ptr as *const libc::c_char, None, |c| {
+            let result = from_c_multistring(ptr as *const libc::c_char, None, None, |c| {
                 let cbytes = c.as_bytes_no_nul();
                 assert_eq!(cbytes, it.next().unwrap().as_bytes());
             });
@@ -393,6 +679,76 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_c_str_to_bytes() {
+        unsafe {
+            let input = b"hi\0";
+            let ptr = input.as_ptr() as *const libc::c_char;
+            assert_eq!(c_str_to_bytes(&ptr), b"hi");
+            assert_eq!(c_str_to_bytes_with_nul(&ptr), b"hi\0");
+            assert_eq!(c_str_to_str(&ptr), Some("hi"));
+
+            let invalid = b"foo\xFF\0";
+            let ptr = invalid.as_ptr() as *const libc::c_char;
+            assert_eq!(c_str_to_str(&ptr), None);
+        }
+    }
+
+    #[test]
+    fn test_from_c_str_bounded() {
+        unsafe {
+            let input = b"hello\0";
+            let ptr = input.as_ptr() as *const libc::c_char;
+            let s: Option<String> = FromCStr::from_c_str_bounded(ptr, 16);
+            assert_eq!(s, Some("hello".to_string()));
+
+            // No NUL within the limit: bail out rather than read past it.
+            let unterminated = b"hello world";
+            let ptr = unterminated.as_ptr() as *const libc::c_char;
+            let s: Option<String> = FromCStr::from_c_str_bounded(ptr, 5);
+            assert_eq!(s, None);
+        }
+    }
+
+    #[test]
+    fn test_c_multistring_iter() {
+        unsafe {
+            let input = b"zero\0one\0\0";
+            let ptr = input.as_ptr() as *const libc::c_char;
+            let entries: Vec<String> = c_multistring_iter(ptr, None)
+                .map(|e| String::from_utf8(e.to_vec()).unwrap())
+                .collect();
+            assert_eq!(entries, vec!["zero".to_string(), "one".to_string()]);
+        }
+    }
+
+    #[test]
+    fn test_to_wide_c_str() {
+        let wide = "zero".to_wide_c_str();
+        let expected: &[u16] = &['z' as u16, 'e' as u16, 'r' as u16, 'o' as u16];
+        assert_eq!(wide.as_units(), expected);
+        unsafe {
+            assert_eq!(*wide.as_ptr().offset(4), 0u16);
+        }
+    }
+
+    #[test]
+    fn test_wide_multistring_parsing() {
+        unsafe {
+            let input: &[u16] = &['z' as u16, 'e' as u16, 'r' as u16, 'o' as u16, 0,
+                                  'o' as u16, 'n' as u16, 'e' as u16, 0,
+                                  0];
+            let expected = ["zero", "one"];
+            let mut it = expected.iter();
+            let result = from_wide_c_multistring(input.as_ptr(), None, |c| {
+                let got: String = String::from_utf16(c.as_units()).unwrap();
+                assert_eq!(got, *it.next().unwrap());
+            });
+            assert_eq!(result, 2);
+            assert!(it.next().is_none());
+        }
+    }
+
     #[test]
     fn test_str_to_c_str() {
         let c_str = "".to_c_str();
@@ -471,6 +827,21 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn test_c_chars() {
+        unsafe {
+            let input = b"hello\0";
+            let ptr = input.as_ptr() as *const libc::c_char;
+            let mut iter = c_chars(&ptr);
+            assert_eq!(iter.next(), Some('h' as libc::c_char));
+            assert_eq!(iter.next(), Some('e' as libc::c_char));
+            assert_eq!(iter.next(), Some('l' as libc::c_char));
+            assert_eq!(iter.next(), Some('l' as libc::c_char));
+            assert_eq!(iter.next(), Some('o' as libc::c_char));
+            assert_eq!(iter.next(), None);
+        }
+    }
+
     #[test]
     fn test_to_c_str_fail() {
         assert!(Thread::spawn(move|| { "he\x00llo".to_c_str() }).join().is_err());